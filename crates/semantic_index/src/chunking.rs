@@ -1,3 +1,4 @@
+use anyhow::{Context as _, Result};
 use language::{with_parser, Grammar, Tree};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -11,21 +12,34 @@ pub struct Chunk {
     pub digest: [u8; 32],
 }
 
-pub fn chunk_text(text: &str, grammar: Option<&Arc<Grammar>>) -> Vec<Chunk> {
+/// Chunks `text` along its syntax tree when a grammar is given, or by lines otherwise.
+///
+/// Fails if the text can't be parsed, either because the grammar was built against an
+/// incompatible tree-sitter ABI or because the parser didn't produce a tree.
+pub fn chunk_text(text: &str, grammar: Option<&Arc<Grammar>>) -> Result<Vec<Chunk>> {
     if let Some(grammar) = grammar {
-        let tree = with_parser(|parser| {
-            parser
-                .set_language(&grammar.ts_language)
-                .expect("incompatible grammar");
-            parser.parse(&text, None).expect("invalid language")
-        });
-
-        chunk_parse_tree(tree, &text, CHUNK_THRESHOLD)
+        Ok(chunk_tree(parse_text(text, grammar)?, text))
     } else {
-        chunk_lines(&text)
+        Ok(chunk_lines(&text))
     }
 }
 
+/// Parses `text` with the given grammar. The resulting tree can be inspected and
+/// then passed to [`chunk_tree`], so that the text only needs to be parsed once.
+pub fn parse_text(text: &str, grammar: &Grammar) -> Result<Tree> {
+    with_parser(|parser| {
+        parser
+            .set_language(&grammar.ts_language)
+            .context("incompatible grammar")?;
+        parser.parse(text, None).context("invalid language")
+    })
+}
+
+/// Chunks `text` along the syntax nodes of its parse tree, as returned by [`parse_text`].
+pub fn chunk_tree(tree: Tree, text: &str) -> Vec<Chunk> {
+    chunk_parse_tree(tree, text, CHUNK_THRESHOLD)
+}
+
 fn chunk_parse_tree(tree: Tree, text: &str, chunk_threshold: usize) -> Vec<Chunk> {
     let mut chunk_ranges = Vec::new();
     let mut cursor = tree.walk();
@@ -49,15 +63,14 @@ fn chunk_parse_tree(tree: Tree, text: &str, chunk_threshold: usize) -> Vec<Chunk
             // If we get here, the node itself has no children but is larger than the threshold.
             // Break its text into arbitrary chunks.
             split_text(text, range.clone(), node.end_byte(), &mut chunk_ranges);
+            range.start = node.end_byte();
         }
         range.end = node.end_byte();
 
         // If we get here, we consumed the node. Advance to the next child, ascending if there isn't one.
         while !cursor.goto_next_sibling() {
             if !cursor.goto_parent() {
-                if !range.is_empty() {
-                    chunk_ranges.push(range);
-                }
+                push_final_range(text, range, chunk_threshold, &mut chunk_ranges);
 
                 return chunk_ranges
                     .into_iter()
@@ -71,7 +84,7 @@ fn chunk_parse_tree(tree: Tree, text: &str, chunk_threshold: usize) -> Vec<Chunk
     }
 }
 
-fn chunk_lines(text: &str) -> Vec<Chunk> {
+pub fn chunk_lines(text: &str) -> Vec<Chunk> {
     let mut chunk_ranges = Vec::new();
     let mut range = 0..0;
 
@@ -92,9 +105,7 @@ fn chunk_lines(text: &str) -> Vec<Chunk> {
         }
     }
 
-    if !range.is_empty() {
-        chunk_ranges.push(range);
-    }
+    push_final_range(text, range, CHUNK_THRESHOLD, &mut chunk_ranges);
 
     chunk_ranges
         .into_iter()
@@ -108,6 +119,26 @@ fn chunk_lines(text: &str) -> Vec<Chunk> {
         .collect()
 }
 
+/// Pushes the final range, extended to cover any text that follows it (such as a
+/// last line without a trailing newline).
+fn push_final_range(
+    text: &str,
+    range: Range<usize>,
+    chunk_threshold: usize,
+    chunk_ranges: &mut Vec<Range<usize>>,
+) {
+    if text.len() - range.start <= chunk_threshold {
+        if range.start < text.len() {
+            chunk_ranges.push(range.start..text.len());
+        }
+    } else {
+        if !range.is_empty() {
+            chunk_ranges.push(range.clone());
+        }
+        split_text(text, range.end..range.end, text.len(), chunk_ranges);
+    }
+}
+
 fn split_text(
     text: &str,
     mut range: Range<usize>,
@@ -214,16 +245,76 @@ mod tests {
         )
     }
 
+    /// Asserts that the chunks cover all of `text` with contiguous ranges that each
+    /// fit within `threshold`.
+    fn assert_chunks_tile(chunks: &[Chunk], text: &str, threshold: usize) {
+        assert_eq!(chunks[0].range.start, 0);
+        assert_eq!(chunks.last().unwrap().range.end, text.len());
+        for chunk in chunks {
+            assert!(chunk.range.len() <= threshold);
+        }
+        for window in chunks.windows(2) {
+            assert_eq!(window[0].range.end, window[1].range.start);
+        }
+    }
+
     #[test]
     fn test_chunk_text() {
         let text = "a\n".repeat(1000);
-        let chunks = chunk_text(&text, None);
+        let chunks = chunk_text(&text, None).unwrap();
         assert_eq!(
             chunks.len(),
             ((2000_f64) / (CHUNK_THRESHOLD as f64)).ceil() as usize
         );
     }
 
+    #[test]
+    fn test_chunk_imports_without_trailing_newline() {
+        let text = "use std::sync::Arc;\nuse std::ops::Range;";
+        let language = setup_rust_language();
+
+        for chunks in [
+            chunk_text(text, None).unwrap(),
+            chunk_text(text, language.grammar()).unwrap(),
+        ] {
+            assert_eq!(chunks.len(), 1);
+            assert_eq!(chunks[0].range, 0..text.len());
+        }
+    }
+
+    #[test]
+    fn test_chunk_text_parse_failure() {
+        // This only covers the parser returning no tree. The `set_language` error for
+        // ABI-incompatible grammars isn't covered, since such a grammar can't be built here.
+        let language = setup_rust_language();
+        let text = TEXT.repeat(100);
+
+        // Make parsing fail by timing it out almost immediately
+        with_parser(|parser| parser.set_timeout_micros(1));
+        let result = chunk_text(&text, language.grammar());
+        with_parser(|parser| {
+            parser.set_timeout_micros(0);
+            parser.reset();
+        });
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_chunk_long_line_without_newline() {
+        // A long minified line is split into byte windows rather than dropped
+        let text = "a".repeat(5000);
+        let chunks = chunk_text(&text, None).unwrap();
+        assert_eq!(chunks.len(), 4);
+        assert_chunks_tile(&chunks, &text, CHUNK_THRESHOLD);
+
+        let language = setup_rust_language();
+        let text = format!("fn f() {{ let x = \"{}\"; }}", "a".repeat(5000));
+        let chunks = chunk_text(&text, language.grammar()).unwrap();
+        assert!(chunks.len() > 1);
+        assert_chunks_tile(&chunks, &text, CHUNK_THRESHOLD);
+    }
+
     #[test]
     fn test_chunk_text_grammar() {
         // Let's set up a big text with some known segments
@@ -231,7 +322,7 @@ mod tests {
 
         let language = setup_rust_language();
 
-        let chunks = chunk_text(TEXT, language.grammar());
+        let chunks = chunk_text(TEXT, language.grammar()).unwrap();
         assert_eq!(chunks.len(), 2);
 
         assert_eq!(chunks[0].range.start, 0);
@@ -242,17 +333,32 @@ mod tests {
         assert_eq!(chunks[1].range.end, 2396);
     }
 
+    #[test]
+    fn test_chunk_multibyte_text() {
+        // Long runs of multi-byte characters must never be split mid-codepoint
+        let text = format!(
+            "fn 数据() {{}}\nlet crab = \"{}\";\n{}\n",
+            "🦀".repeat(CHUNK_THRESHOLD),
+            "日本語".repeat(CHUNK_THRESHOLD),
+        );
+        let language = setup_rust_language();
+
+        let line_chunks = chunk_text(&text, None).unwrap();
+        let syntax_chunks = chunk_text(&text, language.grammar()).unwrap();
+        assert_chunks_tile(&line_chunks, &text, CHUNK_THRESHOLD);
+        assert_chunks_tile(&syntax_chunks, &text, CHUNK_THRESHOLD);
+        for chunk in line_chunks.iter().chain(&syntax_chunks) {
+            assert!(text.is_char_boundary(chunk.range.start));
+            assert!(text.is_char_boundary(chunk.range.end));
+        }
+    }
+
     #[test]
     fn test_chunk_parse_tree() {
         let language = setup_rust_language();
         let grammar = language.grammar().unwrap();
 
-        let tree = with_parser(|parser| {
-            parser
-                .set_language(&grammar.ts_language)
-                .expect("incompatible grammar");
-            parser.parse(TEXT, None).expect("invalid language")
-        });
+        let tree = parse_text(TEXT, grammar).unwrap();
 
         let chunks = chunk_parse_tree(tree, TEXT, 250);
         assert_eq!(chunks.len(), 11);
@@ -265,12 +371,7 @@ mod tests {
         let grammar = language.grammar().unwrap();
 
         let text = r#"fn main() {"#;
-        let tree = with_parser(|parser| {
-            parser
-                .set_language(&grammar.ts_language)
-                .expect("incompatible grammar");
-            parser.parse(text, None).expect("invalid language")
-        });
+        let tree = parse_text(text, grammar).unwrap();
 
         let chunks = chunk_parse_tree(tree, text, 250);
         assert_eq!(chunks.len(), 1);
@@ -284,12 +385,7 @@ mod tests {
         let language = setup_rust_language();
         let grammar = language.grammar().unwrap();
 
-        let tree = with_parser(|parser| {
-            parser
-                .set_language(&grammar.ts_language)
-                .expect("incompatible grammar");
-            parser.parse("", None).expect("invalid language")
-        });
+        let tree = parse_text("", grammar).unwrap();
 
         let chunks = chunk_parse_tree(tree, "", CHUNK_THRESHOLD);
         assert!(chunks.is_empty(), "Chunks should be empty for empty text");
@@ -302,12 +398,7 @@ mod tests {
         let language = setup_rust_language();
         let grammar = language.grammar().unwrap();
 
-        let tree = with_parser(|parser| {
-            parser
-                .set_language(&grammar.ts_language)
-                .expect("incompatible grammar");
-            parser.parse(&large_text, None).expect("invalid language")
-        });
+        let tree = parse_text(&large_text, grammar).unwrap();
 
         let chunks = chunk_parse_tree(tree, &large_text, CHUNK_THRESHOLD);
 
@@ -328,18 +419,37 @@ mod tests {
         assert_eq!(chunks[2].range.end, large_text.len());
     }
 
+    #[test]
+    fn test_large_leaf_node_is_split_once() {
+        let large_text = "static ".to_owned() + "a".repeat(2 * CHUNK_THRESHOLD).as_str() + " = 2";
+
+        let language = setup_rust_language();
+        let grammar = language.grammar().unwrap();
+
+        let tree = parse_text(&large_text, grammar).unwrap();
+
+        let chunks = chunk_parse_tree(tree, &large_text, CHUNK_THRESHOLD);
+        assert_chunks_tile(&chunks, &large_text, CHUNK_THRESHOLD);
+    }
+
+    #[test]
+    fn test_trailing_text_after_last_node() {
+        let text = "fn a() {}\nfn b() {}\n\n";
+        let language = setup_rust_language();
+        let grammar = language.grammar().unwrap();
+
+        let tree = parse_text(text, grammar).unwrap();
+        let chunks = chunk_parse_tree(tree, text, 10);
+        assert_chunks_tile(&chunks, text, 10);
+    }
+
     #[test]
     fn test_multiple_small_nodes() {
         let small_text = "a b c d e f g h i j k l m n o p q r s t u v w x y z";
         let language = setup_rust_language();
         let grammar = language.grammar().unwrap();
 
-        let tree = with_parser(|parser| {
-            parser
-                .set_language(&grammar.ts_language)
-                .expect("incompatible grammar");
-            parser.parse(small_text, None).expect("invalid language")
-        });
+        let tree = parse_text(small_text, grammar).unwrap();
 
         let chunks = chunk_parse_tree(tree, small_text, 5);
         assert!(
@@ -354,12 +464,7 @@ mod tests {
         let language = setup_rust_language();
         let grammar = language.grammar().unwrap();
 
-        let tree = with_parser(|parser| {
-            parser
-                .set_language(&grammar.ts_language)
-                .expect("incompatible grammar");
-            parser.parse(nested_text, None).expect("invalid language")
-        });
+        let tree = parse_text(nested_text, grammar).unwrap();
 
         let chunks = chunk_parse_tree(tree, nested_text, 10);
         assert!(
@@ -375,12 +480,7 @@ mod tests {
         let language = setup_rust_language();
         let grammar = language.grammar().unwrap();
 
-        let tree = with_parser(|parser| {
-            parser
-                .set_language(&grammar.ts_language)
-                .expect("incompatible grammar");
-            parser.parse(mixed_text, None).expect("invalid language")
-        });
+        let tree = parse_text(mixed_text, grammar).unwrap();
 
         let chunks = chunk_parse_tree(tree, mixed_text, 11);
         assert!(
@@ -25,8 +25,12 @@ impl Embedding {
         }
 
         norm = norm.sqrt();
-        for dimension in &mut embedding {
-            *dimension /= norm;
+
+        // Leave zero vectors unchanged rather than filling them with NaNs.
+        if norm > 0. {
+            for dimension in &mut embedding {
+                *dimension /= norm;
+            }
         }
 
         Self(embedding)
@@ -90,16 +94,18 @@ impl<'a> TextToEmbed<'a> {
     }
 }
 
+/// Embeds texts without a service by deriving each vector from the text's digest.
+/// Identical texts get identical embeddings, but the vectors carry no semantic meaning.
 pub struct FakeEmbeddingProvider;
 
 impl EmbeddingProvider for FakeEmbeddingProvider {
     fn embed<'a>(&'a self, texts: &'a [TextToEmbed<'a>]) -> BoxFuture<'a, Result<Vec<Embedding>>> {
         let embeddings = texts
             .iter()
-            .map(|_text| {
+            .map(|text| {
                 let mut embedding = vec![0f32; 1536];
                 for i in 0..embedding.len() {
-                    embedding[i] = i as f32;
+                    embedding[i] = text.digest[i % text.digest.len()] as f32;
                 }
                 Embedding::new(embedding)
             })
@@ -122,4 +128,22 @@ mod test {
         let value: f32 = 1.0 / 3.0_f32.sqrt();
         assert_eq!(normalized, Embedding(vec![value; 3]));
     }
+
+    #[gpui::test]
+    async fn test_fake_embeddings_are_derived_from_digest() {
+        let texts = [
+            TextToEmbed::new("garbage in"),
+            TextToEmbed::new("garbage out"),
+            TextToEmbed::new("garbage in"),
+        ];
+        let embeddings = FakeEmbeddingProvider.embed(&texts).await.unwrap();
+        assert_eq!(embeddings[0], embeddings[2]);
+        assert_ne!(embeddings[0], embeddings[1]);
+    }
+
+    #[gpui::test]
+    fn test_normalize_zero_embedding() {
+        let normalized = Embedding::new(vec![0.0; 3]);
+        assert_eq!(normalized, Embedding(vec![0.0; 3]));
+    }
 }
@@ -2,7 +2,8 @@ mod chunking;
 mod embedding;
 
 use anyhow::{anyhow, Context as _, Result};
-use chunking::{chunk_text, Chunk};
+use chunking::{chunk_lines, chunk_text};
+pub use chunking::{chunk_tree, parse_text, Chunk};
 use collections::{Bound, HashMap};
 pub use embedding::*;
 use fs::Fs;
@@ -548,9 +549,18 @@ impl WorktreeIndex {
                                     .ok();
                                 let grammar =
                                     language.as_ref().and_then(|language| language.grammar());
+                                let chunks = chunk_text(&text, grammar)
+                                    .with_context(|| {
+                                        format!(
+                                            "failed to parse {:?}, chunking by lines",
+                                            entry.path
+                                        )
+                                    })
+                                    .log_err()
+                                    .unwrap_or_else(|| chunk_lines(&text));
                                 let chunked_file = ChunkedFile {
                                     worktree_root: worktree_abs_path.clone(),
-                                    chunks: chunk_text(&text, grammar),
+                                    chunks,
                                     entry,
                                     text,
                                 };